@@ -1,35 +1,178 @@
 use crypto_utils::{Crypto, PrimeDiffieHellman};
+use ed25519_dalek::{Keypair, PublicKey as IdentityPublicKey, Signature, Signer, Verifier};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
 use std::collections::HashMap;
+use std::convert::TryInto;
 use std::io::{self, *};
 use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
 use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
 use std::thread;
 
 const LOCAL: &str = "127.0.0.1:6000";
 
+const SERVER_IDENTITY_KEY_ENV: &str = "CHAT_SERVER_IDENTITY_KEY_PATH";
+const CLIENT_IDENTITY_PUBKEY_ENV: &str = "CHAT_CLIENT_IDENTITY_PUBKEY_PATH";
+
+/// Loads the server's long-term identity keypair from the file at
+/// `$CHAT_SERVER_IDENTITY_KEY_PATH` (the ed25519-dalek 64-byte keypair
+/// encoding). The key is generated once, out of band, and never checked
+/// into source.
+fn load_server_identity() -> io::Result<Keypair> {
+    let path = std::env::var(SERVER_IDENTITY_KEY_ENV).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not set", SERVER_IDENTITY_KEY_ENV),
+        )
+    })?;
+    let bytes = std::fs::read(path)?;
+    Keypair::from_bytes(&bytes).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "malformed server identity keypair file",
+        )
+    })
+}
+
+/// Loads the client's pinned long-term identity public key from the file at
+/// `$CHAT_CLIENT_IDENTITY_PUBKEY_PATH`, provisioned out of band the same way.
+fn load_expected_client_identity() -> io::Result<IdentityPublicKey> {
+    let path = std::env::var(CLIENT_IDENTITY_PUBKEY_ENV).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not set", CLIENT_IDENTITY_PUBKEY_ENV),
+        )
+    })?;
+    let bytes = std::fs::read(path)?;
+    IdentityPublicKey::from_bytes(&bytes).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "malformed client identity public key file",
+        )
+    })
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+const MAC_TAG_LEN: usize = 32;
+const SEQ_LEN: usize = 8;
+
+// Generous for a chat line, small enough that an unauthenticated peer can't
+// use the length prefix to force a multi-gigabyte allocation.
+const MAX_FRAME_LEN: usize = 64 * 1024;
+
+/// Keystream for a single message: `HMAC(key, seq || block_counter)` blocks
+/// concatenated and truncated to `len`, xored with the message bytes by the
+/// caller. `crypto_utils` has no hook to accept an injected encryption key,
+/// so this keys confidentiality off the HKDF-derived `enc_key` instead of
+/// the raw DH secret `crypto.encrypt`/`decrypt` would otherwise use.
+fn keystream(key: &[u8; 32], seq: u64, len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len);
+    let mut counter: u32 = 0;
+    while out.len() < len {
+        let mut block_mac = HmacSha256::new_varkey(key).expect("HMAC accepts any key length");
+        block_mac.update(&seq.to_be_bytes());
+        block_mac.update(&counter.to_be_bytes());
+        out.extend_from_slice(&block_mac.finalize().into_bytes());
+        counter += 1;
+    }
+    out.truncate(len);
+    out
+}
+
+fn xor_with_keystream(data: &[u8], key: &[u8; 32], seq: u64) -> Vec<u8> {
+    keystream(key, seq, data.len())
+        .iter()
+        .zip(data)
+        .map(|(k, d)| k ^ d)
+        .collect()
+}
+
 pub struct EncryptedStream {
     socket: TcpStream,
-    crypto: PrimeDiffieHellman,
+    enc_key: [u8; 32],
+    mac_key: [u8; 32],
+    send_seq: u64,
+    recv_seq: u64,
 }
 
 impl EncryptedStream {
-    pub fn establish(mut socket: TcpStream) -> io::Result<Self> {
+    /// Each side signs `own_ephemeral_pub || peer_ephemeral_pub` from its own
+    /// point of view, so verifying the peer's signature means rebuilding the
+    /// transcript with the two halves swapped.
+    pub fn establish(
+        mut socket: TcpStream,
+        identity: &Keypair,
+        peer_identity: &IdentityPublicKey,
+    ) -> io::Result<Self> {
         let mut crypto = PrimeDiffieHellman::new();
 
         let (mut priv_key, pubkey) = crypto.generate_keys();
-        socket.write(&pubkey.to_vec())?;
+        socket.write_all(&pubkey.to_vec())?;
 
-        let b_bytes = {
+        let peer_pubkey_bytes = {
             let mut data = [0 as u8; 16]; // using 16 byte buffer
-            socket.read(&mut data)?;
+            socket.read_exact(&mut data)?;
             data
         };
 
-        let other_pub_key = crypto.deserialize(&b_bytes);
+        let other_pub_key = crypto.deserialize(&peer_pubkey_bytes);
         crypto.handshake(&mut priv_key, &other_pub_key);
-        println!("Handshake complete!");
 
-        Ok(EncryptedStream { socket, crypto })
+        let (enc_key, mac_key) = {
+            let shared_secret = crypto.shared_secret();
+            let hk = Hkdf::<Sha256>::new(None, &shared_secret);
+
+            let mut enc_key = [0 as u8; 32];
+            hk.expand(b"copilot-study chat encryption key", &mut enc_key)
+                .expect("32 bytes is a valid HKDF output length");
+
+            let mut mac_key = [0 as u8; 32];
+            hk.expand(b"copilot-study chat mac key", &mut mac_key)
+                .expect("32 bytes is a valid HKDF output length");
+
+            (enc_key, mac_key)
+        };
+
+        let mut stream = EncryptedStream {
+            socket,
+            enc_key,
+            mac_key,
+            send_seq: 0,
+            recv_seq: 0,
+        };
+
+        let own_transcript = [pubkey.to_vec(), peer_pubkey_bytes.to_vec()].concat();
+        let own_sig = identity.sign(&own_transcript);
+        stream.send(&base64::encode(own_sig.to_bytes()))?;
+
+        let peer_sig_txt = stream.recv()?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "handshake signature is not valid UTF-8",
+            )
+        })?;
+        let peer_sig_bytes = base64::decode(&peer_sig_txt).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "malformed handshake signature")
+        })?;
+        let peer_sig = Signature::from_bytes(&peer_sig_bytes).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "malformed handshake signature")
+        })?;
+
+        let peer_transcript = [peer_pubkey_bytes.to_vec(), pubkey.to_vec()].concat();
+        peer_identity
+            .verify(&peer_transcript, &peer_sig)
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "peer failed handshake authentication",
+                )
+            })?;
+
+        println!("Handshake complete!");
+        Ok(stream)
     }
 
     pub fn close(&mut self) -> () {
@@ -40,9 +183,26 @@ impl EncryptedStream {
 
     pub fn send(&mut self, msg: &str) -> io::Result<()> {
         let msg_bytes = msg.as_bytes();
-        let encrypted_msg = self.crypto.encrypt(msg_bytes);
         println!("Server Sent: {}", &msg);
-        self.socket.write(&encrypted_msg)?;
+
+        let seq = self.send_seq;
+        self.send_seq += 1;
+
+        let ciphertext = xor_with_keystream(msg_bytes, &self.enc_key, seq);
+
+        let mut mac = HmacSha256::new_varkey(&self.mac_key).expect("HMAC accepts any key length");
+        mac.update(&seq.to_be_bytes());
+        mac.update(&ciphertext);
+        let tag = mac.finalize().into_bytes();
+
+        let mut frame = Vec::with_capacity(SEQ_LEN + MAC_TAG_LEN + ciphertext.len());
+        frame.extend_from_slice(&seq.to_be_bytes());
+        frame.extend_from_slice(&tag);
+        frame.extend_from_slice(&ciphertext);
+
+        let len = frame.len() as u32;
+        self.socket.write_all(&len.to_be_bytes())?;
+        self.socket.write_all(&frame)?;
         Ok(())
     }
 
@@ -51,13 +211,42 @@ impl EncryptedStream {
 
         Ok(EncryptedStream {
             socket,
-            crypto: self.crypto.clone(),
+            enc_key: self.enc_key,
+            mac_key: self.mac_key,
+            send_seq: self.send_seq,
+            recv_seq: self.recv_seq,
         })
     }
 
     pub fn recv(&mut self) -> io::Result<Option<String>> {
-        let raw = Self::receive_raw(&mut self.socket)?;
-        let message = self.crypto.decrypt(&raw);
+        let frame = Self::receive_raw(&mut self.socket)?;
+        if frame.len() < SEQ_LEN + MAC_TAG_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "frame too short to contain a sequence number and MAC tag",
+            ));
+        }
+        let (seq_bytes, rest) = frame.split_at(SEQ_LEN);
+        let (tag, ciphertext) = rest.split_at(MAC_TAG_LEN);
+
+        let seq = u64::from_be_bytes(seq_bytes.try_into().unwrap());
+        if seq != self.recv_seq {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected sequence number, possible replay or dropped frame",
+            ));
+        }
+
+        let mut mac = HmacSha256::new_varkey(&self.mac_key).expect("HMAC accepts any key length");
+        mac.update(seq_bytes);
+        mac.update(ciphertext);
+        mac.verify(tag).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "message authentication failed")
+        })?;
+
+        self.recv_seq += 1;
+
+        let message = xor_with_keystream(ciphertext, &self.enc_key, seq);
         let txt = std::str::from_utf8(&message)
             .ok()
             .map(str::trim)
@@ -66,8 +255,19 @@ impl EncryptedStream {
     }
 
     fn receive_raw(socket: &mut TcpStream) -> io::Result<Vec<u8>> {
-        let mut data = vec![0 as u8; 16]; // using 16 byte buffer
-        socket.read(&mut data).map(|_| data)
+        let mut len_bytes = [0 as u8; 4];
+        socket.read_exact(&mut len_bytes)?;
+        let len = u32::from_be_bytes(len_bytes) as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "frame exceeds maximum allowed size",
+            ));
+        }
+
+        let mut data = vec![0 as u8; len];
+        socket.read_exact(&mut data)?;
+        Ok(data)
     }
 }
 
@@ -77,7 +277,11 @@ enum Message {
     Text(String),
 }
 
-fn accept(channel: Sender<(SocketAddr, Message)>) {
+fn accept(
+    channel: Sender<(SocketAddr, Message)>,
+    identity: Arc<Keypair>,
+    peer_identity: Arc<IdentityPublicKey>,
+) {
     loop {
         let socket = match TcpListener::bind(LOCAL) {
             Ok(socket) => socket,
@@ -88,7 +292,11 @@ fn accept(channel: Sender<(SocketAddr, Message)>) {
             match stream {
                 Ok(stream) => {
                     let local_channel = channel.clone();
-                    thread::spawn(move || handle_stream(stream, local_channel));
+                    let identity = identity.clone();
+                    let peer_identity = peer_identity.clone();
+                    thread::spawn(move || {
+                        handle_stream(stream, local_channel, identity, peer_identity)
+                    });
                 }
                 Err(e) => {
                     eprintln!("Accepting socket shutdown {}", e);
@@ -98,9 +306,14 @@ fn accept(channel: Sender<(SocketAddr, Message)>) {
     }
 }
 
-fn handle_stream(socket: TcpStream, channel: Sender<(SocketAddr, Message)>) -> io::Result<()> {
+fn handle_stream(
+    socket: TcpStream,
+    channel: Sender<(SocketAddr, Message)>,
+    identity: Arc<Keypair>,
+    peer_identity: Arc<IdentityPublicKey>,
+) -> io::Result<()> {
     let addr = socket.peer_addr()?;
-    let mut enc_stream = EncryptedStream::establish(socket)?;
+    let mut enc_stream = EncryptedStream::establish(socket, &identity, &peer_identity)?;
     let foreign_stream = enc_stream.try_clone()?;
 
     // Notify the server that we've established a connection
@@ -122,22 +335,39 @@ fn handle_stream(socket: TcpStream, channel: Sender<(SocketAddr, Message)>) -> i
     }
 }
 
+// Rejects control characters (including embedded newlines) so a username
+// can't be used to forge lines like "* ... was kicked by the operator" in
+// other clients' terminals.
+fn is_valid_username(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| !c.is_control())
+}
+
 struct Client {
     stream: EncryptedStream,
     username: Option<String>,
 }
 
 impl Client {
-    fn send(&mut self, txt: &str) {
+    /// Sends `txt` to the client, logging and returning `false` on failure
+    /// instead of propagating the error, since callers broadcasting to many
+    /// clients need to keep going rather than abort on the first dead socket.
+    fn send(&mut self, txt: &str) -> bool {
         if let Err(e) = self.stream.send(txt) {
             eprintln!("Error sending message to client: {:?}", e);
+            return false;
         }
+        true
     }
 }
 
 #[derive(Default)]
 struct ChatServer {
     clients: HashMap<SocketAddr, Client>,
+    // Whoever connects first is the operator; if the operator disconnects
+    // or is kicked, the role passes to an arbitrary remaining client.
+    // Simple, but enough to gate /kick and /shutdown.
+    operator: Option<SocketAddr>,
+    shutting_down: bool,
 }
 
 impl ChatServer {
@@ -145,6 +375,10 @@ impl ChatServer {
         Default::default()
     }
 
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down
+    }
+
     fn handle_msg(&mut self, addr: SocketAddr, msg: Message) {
         match msg {
             Message::Connected(stream) => {
@@ -153,13 +387,26 @@ impl ChatServer {
                     username: None,
                 };
 
+                if self.operator.is_none() {
+                    self.operator = Some(addr);
+                    client.send("You are the operator for this server.\n");
+                }
+
                 // We ignore the possible failure here because it'll come back to us via a disconnect later
                 client.send("Enter username: ");
 
                 self.clients.insert(addr, client);
             }
             Message::Disconnected => {
-                self.clients.remove(&addr);
+                if let Some(client) = self.clients.remove(&addr) {
+                    if let Some(username) = client.username {
+                        self.broadcast(&format!("* {} left the chat", username));
+                    }
+                }
+
+                if self.operator == Some(addr) {
+                    self.operator = self.clients.keys().next().copied();
+                }
             }
             Message::Text(txt) => {
                 let username = {
@@ -172,20 +419,33 @@ impl ChatServer {
                 let proposed_username = txt.clone();
                 // Negotiating username
                 if username == None {
+                    if !is_valid_username(&proposed_username) {
+                        let client = self
+                            .clients
+                            .get_mut(&addr)
+                            .expect("Text messages should only come from clients that are known");
+                        client.send("Invalid username!\nEnter username: ");
+                        return;
+                    }
+
                     // user name is taken
                     let is_unique = self
                         .clients
                         .values()
                         .find(move |c| c.username.as_ref() == Some(&txt))
                         .is_none();
-                    let client = self
-                        .clients
-                        .get_mut(&addr)
-                        .expect("Text messages should only come from clients that are known");
                     if !is_unique {
+                        let client = self
+                            .clients
+                            .get_mut(&addr)
+                            .expect("Text messages should only come from clients that are known");
                         client.send("Username taken!\nEnter username: ");
                     } else {
-                        client.username = Some(proposed_username);
+                        self.clients
+                            .get_mut(&addr)
+                            .expect("Text messages should only come from clients that are known")
+                            .username = Some(proposed_username.clone());
+                        self.broadcast(&format!("* {} joined the chat", proposed_username));
                     }
                 } else {
                     self.handle_chat_msg(addr, &txt);
@@ -199,16 +459,118 @@ impl ChatServer {
             return;
         }
         if msg.starts_with('/') {
+            if msg == "/list" {
+                let mut usernames: Vec<&str> = self
+                    .clients
+                    .values()
+                    .filter_map(|c| c.username.as_deref())
+                    .collect();
+                usernames.sort();
+
+                let roster = format!(
+                    "Users online ({}):\n{}",
+                    usernames.len(),
+                    usernames.join("\n")
+                );
+                self.clients.get_mut(&addr).unwrap().send(&roster);
+                return;
+            }
+            if let Some(rest) = msg.strip_prefix("/msg ") {
+                let (target, text) = match rest.split_once(' ') {
+                    Some(parts) => parts,
+                    None => {
+                        self.clients
+                            .get_mut(&addr)
+                            .unwrap()
+                            .send("Usage: /msg <username> <text>");
+                        return;
+                    }
+                };
+
+                let target_addr = self
+                    .clients
+                    .iter()
+                    .find(|(_, c)| c.username.as_deref() == Some(target))
+                    .map(|(a, _)| *a);
+
+                match target_addr {
+                    Some(target_addr) => {
+                        let sender = self.clients[&addr].username.clone().unwrap();
+                        let whisper = format!("[whisper from {}] {}", sender, text);
+                        self.clients.get_mut(&target_addr).unwrap().send(&whisper);
+                    }
+                    None => {
+                        self.clients
+                            .get_mut(&addr)
+                            .unwrap()
+                            .send(&format!("No such user: {}", target));
+                    }
+                }
+                return;
+            }
+            if let Some(target) = msg.strip_prefix("/kick ") {
+                if self.operator != Some(addr) {
+                    self.clients
+                        .get_mut(&addr)
+                        .unwrap()
+                        .send("Only the operator can do that.\n");
+                    return;
+                }
+
+                let target_addr = self
+                    .clients
+                    .iter()
+                    .find(|(_, c)| c.username.as_deref() == Some(target))
+                    .map(|(a, _)| *a);
+
+                match target_addr {
+                    Some(target_addr) => {
+                        if let Some(mut client) = self.clients.remove(&target_addr) {
+                            client.stream.close();
+                        }
+                        if self.operator == Some(target_addr) {
+                            self.operator = self.clients.keys().next().copied();
+                        }
+                        self.broadcast(&format!("* {} was kicked by the operator", target));
+                    }
+                    None => {
+                        self.clients
+                            .get_mut(&addr)
+                            .unwrap()
+                            .send(&format!("No such user: {}", target));
+                    }
+                }
+                return;
+            }
+            if msg == "/shutdown" {
+                if self.operator != Some(addr) {
+                    self.clients
+                        .get_mut(&addr)
+                        .unwrap()
+                        .send("Only the operator can do that.\n");
+                    return;
+                }
+
+                self.broadcast("* Server is shutting down");
+                for client in self.clients.values_mut() {
+                    client.stream.close();
+                }
+                self.clients.clear();
+                self.shutting_down = true;
+                return;
+            }
+
             let client = self.clients.get_mut(&addr).unwrap();
             if msg == "/quit" {
                 client.stream.close();
-            } else if msg == "/list" {
-                client.send("Invalid command. Type /help for help.\n");
             } else if msg == "/help" {
                 client.send(
                     "
                     /quit - quit the chat
                     /list - list usernames
+                    /msg <username> <text> - send a private message
+                    /kick <username> - (operator) disconnect a user
+                    /shutdown - (operator) stop the server
                     /help - show this help message",
                 );
             } else {
@@ -218,24 +580,49 @@ impl ChatServer {
             // Invariant, we only call handle_chat_msg for clients with usernames
             let chat = {
                 let uname = self.clients[&addr].username.as_ref().unwrap();
-                format!("{}: {}", uname, msg)
+                let timestamp = chrono::Local::now().format("%H:%M:%S");
+                format!("[{}] {}: {}", timestamp, uname, msg)
             };
 
+            let mut dead_clients = Vec::new();
             for (client_addr, client) in self.clients.iter_mut() {
                 if client_addr != &addr {
-                    client.send(&chat);
+                    if !client.send(&chat) {
+                        dead_clients.push(*client_addr);
+                    }
+                }
+            }
+
+            for dead_addr in dead_clients {
+                if let Some(client) = self.clients.remove(&dead_addr) {
+                    let username = client.username.unwrap_or_else(|| "unknown".to_string());
+                    self.broadcast(&format!("* {} left the chat", username));
                 }
             }
         }
     }
+
+    fn broadcast(&mut self, msg: &str) {
+        for client in self.clients.values_mut() {
+            client.send(msg);
+        }
+    }
 }
 
 fn main() {
+    let identity =
+        Arc::new(load_server_identity().expect("failed to load server identity keypair"));
+    let peer_identity =
+        Arc::new(load_expected_client_identity().expect("failed to load expected client identity"));
+
     let (send, recv) = channel();
-    thread::spawn(move || accept(send));
+    thread::spawn(move || accept(send, identity, peer_identity));
 
     let mut server = ChatServer::new();
     while let Ok((addr, msg)) = recv.recv() {
-        server.handle_msg(addr, msg)
+        server.handle_msg(addr, msg);
+        if server.is_shutting_down() {
+            break;
+        }
     }
 }